@@ -12,6 +12,10 @@ use crate::{DB, config::CONFIG, error::AppError};
 use askama::Template;
 use axum::{
     extract::{Multipart, Path, Query},
+    http::{
+        HeaderMap,
+        header::{ACCEPT, CONTENT_TYPE},
+    },
     response::{IntoResponse, Redirect},
 };
 use axum_extra::{
@@ -19,13 +23,237 @@ use axum_extra::{
     headers::{Cookie, Referer},
 };
 use data_encoding::HEXLOWER;
-use image::ImageFormat;
+use image::{ImageFormat, imageops::FilterType};
+use rand::Rng;
 use ring::digest::{Context, SHA1_FOR_LEGACY_USE_ONLY};
 use serde::Deserialize;
+use std::path::PathBuf;
 
-use tokio::fs::{self, remove_file};
+use tokio::{
+    fs::{self, remove_file},
+    io::AsyncWriteExt,
+};
 use tracing::{error, warn};
 
+/// A multipart field streamed to disk, hashed as it goes.
+struct StagedUpload {
+    tmp_path: PathBuf,
+    hash: String,
+    format: ImageFormat,
+}
+
+/// Streams `field` chunk-by-chunk into a randomly-named file under `CONFIG.tmp_path`,
+/// aborting and deleting the partial file once `CONFIG.max_upload_bytes` is exceeded.
+/// The destination format is guessed from the leading bytes once the stream is done.
+async fn stage_upload(
+    mut field: axum::extract::multipart::Field<'_>,
+) -> Result<StagedUpload, AppError> {
+    let tmp_name = format!("{:016x}.part", rand::rng().random::<u64>());
+    let tmp_path = PathBuf::from(&CONFIG.tmp_path).join(tmp_name);
+    let mut file = fs::File::create(&tmp_path).await?;
+    let mut context = Context::new(&SHA1_FOR_LEGACY_USE_ONLY);
+    let mut header = Vec::with_capacity(32);
+    let mut size: u64 = 0;
+
+    loop {
+        let chunk = match field.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(e) => {
+                drop(file);
+                let _ = remove_file(&tmp_path).await;
+                return Err(AppError::Custom(e.to_string()));
+            }
+        };
+        size += chunk.len() as u64;
+        if size > CONFIG.max_upload_bytes {
+            drop(file);
+            let _ = remove_file(&tmp_path).await;
+            return Err(AppError::Custom("upload exceeds max_upload_bytes".into()));
+        }
+        if header.len() < 32 {
+            let take = (32 - header.len()).min(chunk.len());
+            header.extend_from_slice(&chunk[..take]);
+        }
+        context.update(&chunk);
+        if let Err(e) = file.write_all(&chunk).await {
+            drop(file);
+            let _ = remove_file(&tmp_path).await;
+            return Err(e.into());
+        }
+    }
+    file.flush().await?;
+    drop(file);
+
+    let format = match image::guess_format(&header) {
+        Ok(format) => format,
+        Err(e) => {
+            let _ = remove_file(&tmp_path).await;
+            return Err(e.into());
+        }
+    };
+
+    Ok(StagedUpload {
+        tmp_path,
+        hash: HEXLOWER.encode(context.finish().as_ref()),
+        format,
+    })
+}
+
+/// Atomically moves a staged upload into `dest`, leaving any existing file (same content
+/// hash) untouched so concurrent uploads of identical content can't clobber one another.
+async fn commit_staged_upload(staged: &StagedUpload, dest: &str) -> Result<(), AppError> {
+    if fs::metadata(dest).await.is_ok() {
+        remove_file(&staged.tmp_path).await?;
+    } else {
+        fs::rename(&staged.tmp_path, dest).await?;
+    }
+    Ok(())
+}
+
+/// Atomically moves a staged upload into `dest`, replacing whatever is already there.
+/// Used for the fixed-name avatar/inn-icon slots, where a new upload is meant to
+/// supersede the old file rather than dedup against it.
+async fn replace_with_staged_upload(staged: &StagedUpload, dest: &str) -> Result<(), AppError> {
+    fs::rename(&staged.tmp_path, dest).await?;
+    Ok(())
+}
+
+/// Applies the EXIF orientation tag (if any) to `img` so the stored pixels are upright
+/// regardless of how the camera held the orientation flag, per the EXIF spec's 8 values.
+fn apply_exif_orientation(img: image::DynamicImage, orientation: u32) -> image::DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// Reads the EXIF orientation tag from `bytes`, if present.
+fn read_exif_orientation(bytes: &[u8]) -> Option<u32> {
+    let mut cursor = std::io::Cursor::new(bytes);
+    let exif = exif::Reader::new()
+        .read_from_container(&mut cursor)
+        .ok()?;
+    let field = exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?;
+    field.value.get_uint(0)
+}
+
+/// When `CONFIG.strip_metadata` is set, decodes `staged`'s temp file and re-encodes it so no
+/// EXIF (GPS, camera serial, timestamp) survives into the stored file, rotating/flipping it
+/// upright first per its EXIF orientation tag if one is present. This always re-encodes, even
+/// for already-upright images with no orientation tag, since those still commonly carry other
+/// EXIF data that needs stripping. The temp file and `staged.hash` are updated in place, since
+/// stripping changes the bytes that get hashed into the final filename. JPEG is the only
+/// format that commonly carries EXIF, so other formats are left untouched.
+async fn strip_metadata(staged: &mut StagedUpload) -> Result<(), AppError> {
+    if !CONFIG.strip_metadata || staged.format != ImageFormat::Jpeg {
+        return Ok(());
+    }
+
+    let bytes = fs::read(&staged.tmp_path).await?;
+    let orientation = read_exif_orientation(&bytes).unwrap_or(1);
+
+    let img = image::load_from_memory_with_format(&bytes, staged.format)?;
+    let img = apply_exif_orientation(img, orientation);
+
+    let mut out = Vec::new();
+    img.write_to(&mut std::io::Cursor::new(&mut out), staged.format)?;
+
+    let mut context = Context::new(&SHA1_FOR_LEGACY_USE_ONLY);
+    context.update(&out);
+    staged.hash = HEXLOWER.encode(context.finish().as_ref());
+
+    fs::write(&staged.tmp_path, &out).await?;
+    Ok(())
+}
+
+/// The `CONFIG.preferred_format` setting, resolved to an `image` crate format.
+fn preferred_format() -> Option<ImageFormat> {
+    match CONFIG.preferred_format.as_deref() {
+        Some("webp") => Some(ImageFormat::WebP),
+        Some("avif") => Some(ImageFormat::Avif),
+        _ => None,
+    }
+}
+
+/// Re-encodes an already-saved upload into `CONFIG.preferred_format`, storing the transcoded
+/// variant alongside the original at `{dest_stem}.{webp,avif}` so clients that advertise
+/// support via `Accept` can be served the smaller file. `dest_stem` is the destination path
+/// without its extension, e.g. the content hash under `CONFIG.upload_path` for gallery
+/// uploads, or the fixed per-user/per-inn path for avatars and inn icons.
+async fn store_transcoded_variant(
+    source: &[u8],
+    source_format: ImageFormat,
+    dest_stem: &str,
+) -> Result<(), AppError> {
+    let Some(target_format) = preferred_format() else {
+        return Ok(());
+    };
+    if source_format == target_format {
+        return Ok(());
+    }
+    let img = image::load_from_memory_with_format(source, source_format)?;
+    let extension = target_format.extensions_str().first().unwrap_or(&"webp");
+    let mut out = Vec::new();
+    img.write_to(&mut std::io::Cursor::new(&mut out), target_format)?;
+    fs::write(format!("{dest_stem}.{extension}"), &out).await?;
+    Ok(())
+}
+
+/// Increments the shared reference count for `filename` in the `image_refcount` partition.
+/// Content-hash filenames are shared across uploads of identical bytes, so every successful
+/// write registers a reference before any deleter can safely remove the file. Uses
+/// `fetch_update` rather than a plain get-then-insert so two concurrent uploads of the same
+/// content (or an upload racing a delete) can't read the same count and clobber each other.
+fn incr_image_refcount(filename: &str) -> Result<(), AppError> {
+    let refcounts = DB.open_partition("image_refcount", Default::default())?;
+    refcounts.fetch_update(filename, |existing| {
+        let count = existing.map_or(1, |v| u8_slice_to_u32(v) + 1);
+        Some(u32_to_ivec(count))
+    })?;
+    Ok(())
+}
+
+/// Decrements the shared reference count for `filename`, removing the row once it reaches
+/// zero, and returns the count after the decrement so the caller knows whether it's safe to
+/// `remove_file` the underlying image. Atomic for the same reason as `incr_image_refcount`.
+fn decr_image_refcount(filename: &str) -> Result<u32, AppError> {
+    let refcounts = DB.open_partition("image_refcount", Default::default())?;
+    let mut after = 0;
+    refcounts.fetch_update(filename, |existing| {
+        after = existing.map_or(0, |v| u8_slice_to_u32(v).saturating_sub(1));
+        if after == 0 { None } else { Some(u32_to_ivec(after)) }
+    })?;
+    Ok(after)
+}
+
+/// Removes the `(uid, img_id)` row from `user_uploads` and drops the shared reference,
+/// deleting the underlying file once no uploader references it anymore. Shared by the
+/// per-user delete path and the admin-wide purge, which can remove images regardless of
+/// ownership.
+async fn purge_uploaded_image(uid: u32, img_id: u32) -> Result<(), AppError> {
+    let k = [u32_to_ivec(uid), u32_to_ivec(img_id)].concat();
+    let tree = DB.open_partition("user_uploads", Default::default())?;
+    if let Some(v) = tree.take(&k)? {
+        let img = String::from_utf8_lossy(&v).to_string();
+        if decr_image_refcount(&img)? == 0 {
+            let path = format!("{}/{img}", CONFIG.upload_path);
+            remove_file(path).await?;
+        }
+    } else {
+        return Err(AppError::NotFound);
+    }
+    let image_meta = DB.open_partition("image_meta", Default::default())?;
+    image_meta.remove(&k)?;
+    Ok(())
+}
+
 #[derive(Deserialize)]
 pub(crate) struct UploadPicParams {
     page_type: String,
@@ -63,28 +291,165 @@ pub(crate) async fn upload_pic_post(
         _ => return Err(AppError::NotFound),
     };
 
-    if let Some(field) = multipart.next_field().await.unwrap() {
-        let data = match field.bytes().await {
-            Ok(data) => data,
-            Err(e) => {
-                error!("{:?}", e);
-                return Ok(e.into_response());
+    if let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| AppError::Custom(e.to_string()))?
+    {
+        let staged = stage_upload(field).await?;
+        let data = fs::read(&staged.tmp_path).await?;
+        if let Err(e) = image::load_from_memory_with_format(&data, staged.format) {
+            let _ = remove_file(&staged.tmp_path).await;
+            return Err(e.into());
+        }
+        replace_with_staged_upload(&staged, &fname).await?;
+        // Avatars/inn icons live at a fixed per-user/per-inn path, not a content-hash one, so
+        // they're never deduplicated and nothing would ever decrement a count registered here
+        // (`decr_image_refcount` is only reachable via `purge_uploaded_image`, which is keyed
+        // off `user_uploads` rows that these writes never create). Refcounting buys nothing
+        // for this path and would just leak a row per re-upload.
+        if let Some((dest_stem, _)) = fname.rsplit_once('.') {
+            if let Err(e) = store_transcoded_variant(&data, staged.format, dest_stem).await {
+                warn!("{:?}", e);
             }
-        };
-        let image_format_detected = image::guess_format(&data)?;
-        image::load_from_memory_with_format(&data, image_format_detected)?;
-        fs::write(fname, &data).await.unwrap();
+        }
     }
 
     Ok(Redirect::to(&target).into_response())
 }
 
+/// Resize strategy for `GET /image/:filename`.
+enum Fit {
+    Contain,
+    Cover,
+}
+
+impl Fit {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ImageParams {
+    w: Option<u32>,
+    h: Option<u32>,
+    fit: Option<String>,
+}
+
+/// Dimension presets the thumbnail endpoint will serve; anything else is rejected so the
+/// processed-variant cache can't be flooded with arbitrary width/height combinations.
+const ALLOWED_DIMENSIONS: &[(u32, u32)] = &[(64, 64), (160, 160), (320, 320), (640, 640)];
+
+/// Accepts only a non-empty hex stem of at most 64 characters followed by one of the image
+/// extensions this module stores (`png`, `jpg`, `jpeg`, `gif`, `webp`, `avif`), which covers the
+/// `{sha1-hex}.{ext}` names `upload_post` produces; the stem length is not pinned to 40. `filename`
+/// comes straight from the `Path` extractor, so without this check a path-traversal payload
+/// (e.g. `../../../etc/passwd`) would flow straight into the `fs::read`/`fs::write` calls below.
+fn is_valid_image_filename(filename: &str) -> bool {
+    let Some((stem, ext)) = filename.rsplit_once('.') else {
+        return false;
+    };
+    !stem.is_empty()
+        && stem.len() <= 64
+        && stem.bytes().all(|b| b.is_ascii_hexdigit())
+        && matches!(ext, "png" | "jpg" | "jpeg" | "gif" | "webp" | "avif")
+}
+
+fn content_type_for(filename: &str) -> &'static str {
+    match filename.rsplit('.').next() {
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        _ => "image/jpeg",
+    }
+}
+
+/// Picks the best on-disk variant of `filename` for a client's `Accept` header: the
+/// transcoded sibling (same content hash, different extension) if it exists on disk and the
+/// client advertises support for it, falling back to the original otherwise.
+async fn negotiate_variant(filename: &str, accept: &str) -> String {
+    let Some((stem, _)) = filename.rsplit_once('.') else {
+        return filename.to_string();
+    };
+    for (mime, extension) in [("image/avif", "avif"), ("image/webp", "webp")] {
+        if accept.contains(mime) {
+            let candidate = format!("{stem}.{extension}");
+            if fs::metadata(format!("{}/{candidate}", &CONFIG.upload_path))
+                .await
+                .is_ok()
+            {
+                return candidate;
+            }
+        }
+    }
+    filename.to_string()
+}
+
+/// `GET /image/:filename`
+pub(crate) async fn image_get(
+    Path(filename): Path<String>,
+    Query(params): Query<ImageParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    let w = params.w.unwrap_or(320);
+    let h = params.h.unwrap_or(320);
+    let fit = match params.fit.as_deref() {
+        Some("cover") => Fit::Cover,
+        _ => Fit::Contain,
+    };
+
+    if !ALLOWED_DIMENSIONS.contains(&(w, h)) {
+        return Err(AppError::NotFound);
+    }
+    if !is_valid_image_filename(&filename) {
+        return Err(AppError::NotFound);
+    }
+
+    let accept = headers
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    let filename = negotiate_variant(&filename, accept).await;
+
+    let cache_path = format!(
+        "{}/{filename}_{w}x{h}_{}",
+        &CONFIG.image_cache_path,
+        fit.as_str()
+    );
+    if let Ok(cached) = fs::read(&cache_path).await {
+        return Ok(([(CONTENT_TYPE, content_type_for(&filename))], cached));
+    }
+
+    let source = fs::read(format!("{}/{filename}", &CONFIG.upload_path))
+        .await
+        .map_err(|_| AppError::NotFound)?;
+    let format = image::guess_format(&source)?;
+    let img = image::load_from_memory_with_format(&source, format)?;
+    let resized = match fit {
+        Fit::Contain => img.resize(w, h, FilterType::Lanczos3),
+        Fit::Cover => img.resize_to_fill(w, h, FilterType::Lanczos3),
+    };
+
+    let mut out = Vec::new();
+    resized.write_to(&mut std::io::Cursor::new(&mut out), format)?;
+    if let Err(e) = fs::write(&cache_path, &out).await {
+        warn!("{:?}", e);
+    }
+
+    Ok(([(CONTENT_TYPE, content_type_for(&filename))], out))
+}
+
 /// Page data: `gallery.html`
 #[derive(Template)]
 #[template(path = "gallery.html")]
 struct PageGallery<'a> {
     page_data: PageData<'a>,
-    imgs: Vec<(u32, String)>,
+    imgs: Vec<(u32, String, String)>,
     anchor: usize,
     is_desc: bool,
     n: usize,
@@ -112,6 +477,7 @@ pub(crate) async fn gallery(
 
     let mut imgs = Vec::with_capacity(n);
     let ks = DB.open_partition("user_uploads", Default::default())?;
+    let image_meta = DB.open_partition("image_meta", Default::default())?;
     let iter = ks.inner().prefix(u32_to_ivec(uid));
     let iter = if is_desc {
         IterType::Rev(iter.rev())
@@ -127,7 +493,11 @@ pub(crate) async fn gallery(
         let (k, v) = i?;
         let img_id = u8_slice_to_u32(&k[4..]);
         let img = String::from_utf8_lossy(&v).to_string();
-        imgs.push((img_id, img));
+        let caption = image_meta
+            .get(&k)?
+            .map(|v| String::from_utf8_lossy(&v).to_string())
+            .unwrap_or_default();
+        imgs.push((img_id, img, caption));
 
         if imgs.len() >= n {
             break;
@@ -161,27 +531,7 @@ pub(crate) async fn image_delete(
         return Err(AppError::Unauthorized);
     }
 
-    let k = [u32_to_ivec(uid), u32_to_ivec(img_id)].concat();
-    let tree = DB.open_partition("user_uploads", Default::default())?;
-    if let Some(v1) = tree.take(&k)? {
-        // When the same pictures uploaded, only one will be saved. So when deleting, we must check that.
-        let mut count = 0;
-        for i in tree.inner().iter() {
-            let (_, v2) = i?;
-            if v1 == v2 {
-                count += 1;
-                break;
-            }
-        }
-
-        if count == 0 {
-            let img = String::from_utf8_lossy(&v1);
-            let path = format!("{}/{}", CONFIG.upload_path, img);
-            remove_file(path).await?;
-        }
-    } else {
-        return Err(AppError::NotFound);
-    }
+    purge_uploaded_image(uid, img_id).await?;
 
     if uid != claim.uid {
         add_notification(&DB, uid, NtType::ImageDelete, claim.uid, img_id)?;
@@ -195,6 +545,94 @@ pub(crate) async fn image_delete(
     Ok(Redirect::to(&target))
 }
 
+/// Page data: `admin_media.html`
+#[derive(Template)]
+#[template(path = "admin_media.html")]
+struct PageAdminMedia<'a> {
+    page_data: PageData<'a>,
+    imgs: Vec<(u32, u32, String, u64)>,
+    anchor: usize,
+    is_desc: bool,
+    n: usize,
+}
+
+/// `GET /admin/media`
+pub(crate) async fn admin_media(
+    cookie: Option<TypedHeader<Cookie>>,
+    Query(params): Query<ParamsTag>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    if Role::from(claim.role) != Role::Admin {
+        return Err(AppError::Unauthorized);
+    }
+
+    let has_unread = User::has_unread(&DB, claim.uid)?;
+
+    let anchor = params.anchor.unwrap_or(0);
+    let is_desc = params.is_desc.unwrap_or(true);
+    let n = 30;
+
+    let mut imgs = Vec::with_capacity(n);
+    let ks = DB.open_partition("user_uploads", Default::default())?;
+    let iter = ks.inner().iter();
+    let iter = if is_desc {
+        IterType::Rev(iter.rev())
+    } else {
+        IterType::Fwd(iter)
+    };
+
+    for (idx, i) in iter.enumerate() {
+        if idx < anchor {
+            continue;
+        }
+
+        let (k, v) = i?;
+        let uid = u8_slice_to_u32(&k[..4]);
+        let img_id = u8_slice_to_u32(&k[4..]);
+        let filename = String::from_utf8_lossy(&v).to_string();
+        let size = fs::metadata(format!("{}/{filename}", &CONFIG.upload_path))
+            .await
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        imgs.push((uid, img_id, filename, size));
+
+        if imgs.len() >= n {
+            break;
+        }
+    }
+
+    let page_data = PageData::new("admin media", &site_config, Some(claim), has_unread);
+    let page_admin_media = PageAdminMedia {
+        page_data,
+        imgs,
+        anchor,
+        is_desc,
+        n,
+    };
+
+    Ok(into_response(&page_admin_media))
+}
+
+/// `POST /admin/media/purge/:uid/:img_id`
+pub(crate) async fn admin_media_purge(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path((uid, img_id)): Path<(u32, u32)>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    if Role::from(claim.role) != Role::Admin {
+        return Err(AppError::Unauthorized);
+    }
+
+    purge_uploaded_image(uid, img_id).await?;
+    add_notification(&DB, uid, NtType::ImageDelete, claim.uid, img_id)?;
+
+    Ok(Redirect::to("/admin/media"))
+}
+
 /// Page data: `upload.html`
 #[derive(Template)]
 #[template(path = "upload.html")]
@@ -237,53 +675,90 @@ pub(crate) async fn upload_post(
     let user_uploads = DB
         .inner()
         .open_partition("user_uploads", Default::default())?;
+    let image_meta = DB
+        .inner()
+        .open_partition("image_meta", Default::default())?;
+    let mut last_img_id: Option<u32> = None;
     while let Some(field) = multipart
         .next_field()
         .await
         .map_err(|e| AppError::Custom(e.to_string()))?
     {
-        let data = match field.bytes().await {
-            Ok(data) => data,
-            Err(e) => {
-                warn!("{:?}", e);
-                continue; // @TODO frontend alert
+        if field.name() == Some("caption") {
+            if let Some(img_id) = last_img_id.take() {
+                let caption = field
+                    .text()
+                    .await
+                    .map_err(|e| AppError::Custom(e.to_string()))?;
+                let caption = caption.trim();
+                if !caption.is_empty() {
+                    let k = [u32_to_ivec(claim.uid), u32_to_ivec(img_id)].concat();
+                    batch.insert(&image_meta, k, caption.as_bytes());
+                }
             }
-        };
-        let format = match image::guess_format(&data) {
-            Ok(format) => format,
+            continue;
+        }
+
+        let mut staged = match stage_upload(field).await {
+            Ok(staged) => staged,
             Err(e) => {
                 warn!("{:?}", e);
+                last_img_id = None;
                 continue; // @TODO frontend alert
             }
         };
         if !matches!(
-            format,
+            staged.format,
             ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP | ImageFormat::Gif
         ) {
-            warn!("Unsupported image format: {:?}", format);
-            continue;
-        } // @TODO frontend alert
-        match format.extensions_str().first() {
+            warn!("Unsupported image format: {:?}", staged.format);
+            let _ = remove_file(&staged.tmp_path).await;
+            last_img_id = None;
+            continue; // @TODO frontend alert
+        }
+        if let Err(e) = strip_metadata(&mut staged).await {
+            warn!("{:?}", e);
+            let _ = remove_file(&staged.tmp_path).await;
+            last_img_id = None;
+            continue; // @TODO frontend alert
+        }
+        match staged.format.extensions_str().first() {
             Some(extension) => {
-                let mut context = Context::new(&SHA1_FOR_LEGACY_USE_ONLY);
-                context.update(&data);
-                let filename = format!(
-                    "{}.{extension}",
-                    &HEXLOWER.encode(context.finish().as_ref()),
-                );
-                if let Err(e) =
-                    fs::write(format!("{}/{filename}", &CONFIG.upload_path), &data).await
-                {
+                let filename = format!("{}.{extension}", &staged.hash);
+                let dest = format!("{}/{filename}", &CONFIG.upload_path);
+                if let Err(e) = commit_staged_upload(&staged, &dest).await {
                     error!("{:?}", e);
+                    last_img_id = None;
                     continue; // @TODO frontend alert
                 }
+                // Register the reference *before* the `user_uploads` row that points at it
+                // becomes visible via `batch.commit()` below. Otherwise a concurrent delete of
+                // the last other reference to this same content could see a refcount of zero
+                // and `remove_file` the image while this row is already committed and pointing
+                // at it — a live gallery entry referencing a deleted file. Incrementing first
+                // only risks leaking a count of 1 if the batch never commits, which is
+                // recoverable by an admin audit rather than silent data loss.
+                incr_image_refcount(&filename)?;
+                if let Ok(source) = fs::read(&dest).await {
+                    let dest_stem = format!("{}/{}", &CONFIG.upload_path, staged.hash);
+                    if let Err(e) =
+                        store_transcoded_variant(&source, staged.format, &dest_stem).await
+                    {
+                        warn!("{:?}", e);
+                    }
+                }
                 let img_id = incr_id(&DB, "imgs_count")?; // @TODO is this really work before the commit?
                 let k = [u32_to_ivec(claim.uid), u32_to_ivec(img_id)].concat();
                 batch.insert(&user_uploads, k, filename.as_bytes());
+                last_img_id = Some(img_id);
 
                 imgs.push(filename)
             }
-            None => warn!("Unsupported image extension"), // @TODO frontend alert
+            None => {
+                warn!("Unsupported image extension");
+                let _ = remove_file(&staged.tmp_path).await;
+                last_img_id = None;
+            } // @TODO frontend alert
         }
     }
 
@@ -300,3 +775,80 @@ pub(crate) async fn upload_post(
 
     Ok(into_response(&page_upload))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_valid_image_filename_rejects_path_traversal_and_bad_shapes() {
+        assert!(is_valid_image_filename(
+            "3f786850e387550fdab836ed7e6dc881de23001.jpg"
+        ));
+        assert!(!is_valid_image_filename("../../../etc/passwd"));
+        assert!(!is_valid_image_filename("..%2f..%2fetc%2fpasswd.jpg"));
+        assert!(!is_valid_image_filename(".jpg"));
+        assert!(!is_valid_image_filename("not-hex-at-all.jpg"));
+        assert!(!is_valid_image_filename("deadbeef.exe"));
+        assert!(!is_valid_image_filename("deadbeef"));
+    }
+
+    #[tokio::test]
+    async fn stage_upload_aborts_and_cleans_up_past_the_size_cap() {
+        use axum::{body::Body, extract::FromRequest, http::Request};
+
+        let boundary = "stage-upload-size-cap-test-boundary";
+        let oversized = vec![0u8; (CONFIG.max_upload_bytes + 1) as usize];
+        let mut body = Vec::new();
+        body.extend_from_slice(format!("--{boundary}\r\n").as_bytes());
+        body.extend_from_slice(
+            b"Content-Disposition: form-data; name=\"file\"; filename=\"big.bin\"\r\n\r\n",
+        );
+        body.extend_from_slice(&oversized);
+        body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+        let request = Request::builder()
+            .header(
+                "content-type",
+                format!("multipart/form-data; boundary={boundary}"),
+            )
+            .body(Body::from(body))
+            .unwrap();
+
+        let mut multipart = Multipart::from_request(request, &()).await.unwrap();
+        let field = multipart.next_field().await.unwrap().unwrap();
+
+        let before = std::fs::read_dir(&CONFIG.tmp_path).unwrap().count();
+        let result = stage_upload(field).await;
+        assert!(result.is_err());
+        let after = std::fs::read_dir(&CONFIG.tmp_path).unwrap().count();
+        assert_eq!(
+            before, after,
+            "the partial temp file must be removed once the size cap aborts the stream"
+        );
+    }
+
+    #[test]
+    fn decrementing_one_of_two_shared_references_keeps_the_file_live() {
+        // Simulates two uploaders sharing one content-hash filename: both register a
+        // reference, then one of them deletes their row. The refcount must stay above zero
+        // so the still-referenced file is never `remove_file`'d out from under the other row.
+        let filename = "test-fixture-shared-refcount.jpg";
+
+        incr_image_refcount(filename).unwrap();
+        incr_image_refcount(filename).unwrap();
+
+        assert_eq!(decr_image_refcount(filename).unwrap(), 1);
+
+        let refcounts = DB
+            .open_partition("image_refcount", Default::default())
+            .unwrap();
+        assert!(
+            refcounts.get(filename).unwrap().is_some(),
+            "a still-referenced file must not be treated as safe to remove"
+        );
+
+        assert_eq!(decr_image_refcount(filename).unwrap(), 0);
+        assert!(refcounts.get(filename).unwrap().is_none());
+    }
+}